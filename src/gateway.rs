@@ -0,0 +1,372 @@
+//! UPnP/IGD gateway discovery.
+//!
+//! An alternative first-hop source to [`trace_to_public_ip`](crate::trace_to_public_ip): instead
+//! of tracerouting towards a public destination, discover the LAN's router directly via UPnP
+//! Internet Gateway Device (IGD) discovery and monitor that address. This is useful where the
+//! first traceroute hop is filtered or rate-limited, giving a deterministic "my own router"
+//! target instead.
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+const SSDP_MULTICAST_ADDR: &str = "239.255.255.250:1900";
+const SSDP_SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SSDP_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Discover the LAN's UPnP Internet Gateway Device and return its LAN address, to be monitored
+/// in place of a traceroute-discovered first hop.
+pub async fn discover_gateway_ip() -> Result<IpAddr> {
+    let description_url = ssdp_discover_igd().await?;
+    let addr = description_url.host;
+
+    // Confirm the device description actually claims to be an IGD before we trust it.
+    let description = http_get(&description_url).await?;
+    if !description.contains("InternetGatewayDevice") {
+        return Err(anyhow!(
+            "device at {} does not advertise an InternetGatewayDevice",
+            description_url
+        ));
+    }
+
+    Ok(addr)
+}
+
+/// Ask the LAN's IGD for its WAN-facing (external) IP address via the
+/// `GetExternalIPAddress` SOAP action.
+pub async fn discover_external_ip() -> Result<IpAddr> {
+    let description_url = ssdp_discover_igd().await?;
+    let description = http_get(&description_url).await?;
+    let (control_url, service) = parse_control_url(&description_url, &description)?;
+
+    let response = soap_get_external_ip(&control_url, service).await?;
+    parse_external_ip(&response)
+}
+
+/// An HTTP(-ish) URL, parsed just far enough to make a raw request: `http://host:port/path`.
+struct Url {
+    host: IpAddr,
+    port: u16,
+    path: String,
+}
+
+impl std::fmt::Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+impl std::str::FromStr for Url {
+    type Err = anyhow::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("only http:// URLs are supported, got: {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[..idx], &rest[idx..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse()?),
+            None => (authority, 80),
+        };
+
+        Ok(Url {
+            host: host.parse()?,
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Send an SSDP M-SEARCH for an Internet Gateway Device and return the `LOCATION` of the first
+/// reply, i.e. the URL of its device description.
+async fn ssdp_discover_igd() -> Result<Url> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {SSDP_MULTICAST_ADDR}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\
+         ST: {SSDP_SEARCH_TARGET}\r\n\r\n"
+    );
+    socket
+        .send_to(search.as_bytes(), SSDP_MULTICAST_ADDR)
+        .await?;
+
+    let mut buf = [0u8; 2048];
+    let response = timeout(SSDP_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| anyhow!("no SSDP response from a gateway within {:?}", SSDP_TIMEOUT))??;
+    let response = String::from_utf8_lossy(&buf[..response]);
+
+    parse_location(&response)
+}
+
+/// Pull the `LOCATION` header out of an SSDP M-SEARCH response and parse it as a [`Url`].
+fn parse_location(response: &str) -> Result<Url> {
+    let location = response
+        .lines()
+        .find_map(|line| line.to_ascii_uppercase().starts_with("LOCATION:").then_some(line))
+        .and_then(|line| line.split_once(':'))
+        .map(|(_, value)| value.trim())
+        .ok_or_else(|| anyhow!("SSDP response had no LOCATION header"))?;
+
+    location.parse()
+}
+
+/// The `WANIPConnection`/`WANPPPConnection` service URNs we know how to drive, across the v1 and
+/// v2 IGD profiles - routers are free to expose either.
+const WAN_CONNECTION_SERVICES: [&str; 4] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:2",
+];
+
+/// Pull the `controlURL` for the IGD's WAN connection service out of a device description
+/// document, along with the service URN it was found under. We only need the one service, so a
+/// substring search is enough - no need to pull in a full XML parser for this.
+fn parse_control_url<'a>(
+    description_url: &Url,
+    description: &'a str,
+) -> Result<(Url, &'a str)> {
+    for service in WAN_CONNECTION_SERVICES {
+        if let Some(service_start) = description.find(service) {
+            let after_service = &description[service_start..];
+            if let Some(control_url) = extract_tag(after_service, "controlURL") {
+                let url = if control_url.starts_with("http://") {
+                    control_url.parse()?
+                } else {
+                    let path = if control_url.starts_with('/') {
+                        control_url.to_string()
+                    } else {
+                        format!("/{}", control_url)
+                    };
+                    Url {
+                        host: description_url.host,
+                        port: description_url.port,
+                        path,
+                    }
+                };
+                return Ok((url, &description[service_start..service_start + service.len()]));
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "no WANIPConnection/WANPPPConnection controlURL found in device description"
+    ))
+}
+
+/// Send the `GetExternalIPAddress` SOAP action to the IGD's control URL, against the given
+/// WAN connection service URN (as found by [`parse_control_url`]).
+async fn soap_get_external_ip(control_url: &Url, service: &str) -> Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+<s:Body><u:GetExternalIPAddress xmlns:u=\"{service}\"/>\
+</s:Body></s:Envelope>"
+    );
+
+    let request = format!(
+        "POST {path} HTTP/1.1\r\n\
+         Host: {host}:{port}\r\n\
+         Content-Type: text/xml; charset=\"utf-8\"\r\n\
+         SOAPAction: \"{service}#GetExternalIPAddress\"\r\n\
+         Content-Length: {len}\r\n\
+         Connection: close\r\n\r\n\
+         {body}",
+        path = control_url.path,
+        host = control_url.host,
+        port = control_url.port,
+        len = body.len(),
+        body = body,
+    );
+
+    send_request(control_url.host, control_url.port, &request).await
+}
+
+/// Parse the `NewExternalIPAddress` out of a `GetExternalIPAddress` SOAP response.
+fn parse_external_ip(response: &str) -> Result<IpAddr> {
+    extract_tag(response, "NewExternalIPAddress")
+        .ok_or_else(|| anyhow!("SOAP response had no NewExternalIPAddress"))?
+        .parse()
+        .map_err(|_| anyhow!("NewExternalIPAddress was not a valid IP address"))
+}
+
+/// Fetch a URL with a bare HTTP/1.1 GET and return the response body.
+async fn http_get(url: &Url) -> Result<String> {
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}:{port}\r\nConnection: close\r\n\r\n",
+        path = url.path,
+        host = url.host,
+        port = url.port,
+    );
+    send_request(url.host, url.port, &request).await
+}
+
+/// Open a TCP connection, send a raw HTTP request, and return just the response body. Bounded by
+/// `HTTP_TIMEOUT` so a device that never closes the connection can't hang the caller forever.
+async fn send_request(host: IpAddr, port: u16, request: &str) -> Result<String> {
+    timeout(HTTP_TIMEOUT, send_request_inner(host, port, request))
+        .await
+        .map_err(|_| anyhow!("request to {}:{} timed out after {:?}", host, port, HTTP_TIMEOUT))?
+}
+
+async fn send_request_inner(host: IpAddr, port: u16, request: &str) -> Result<String> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .ok_or_else(|| anyhow!("malformed HTTP response from {}:{}", host, port))
+}
+
+/// Extract the text content of the first `<tag>...</tag>` in `xml`. Good enough for the small,
+/// predictable UPnP documents we read here.
+fn extract_tag<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&close)? + start;
+    Some(xml[start..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_parses_host_port_and_path() {
+        let url: Url = "http://192.168.1.1:5000/rootDesc.xml".parse().unwrap();
+        assert_eq!(url.host, IpAddr::from([192, 168, 1, 1]));
+        assert_eq!(url.port, 5000);
+        assert_eq!(url.path, "/rootDesc.xml");
+    }
+
+    #[test]
+    fn url_defaults_to_port_80_and_root_path() {
+        let url: Url = "http://192.168.1.1".parse().unwrap();
+        assert_eq!(url.port, 80);
+        assert_eq!(url.path, "/");
+    }
+
+    #[test]
+    fn url_rejects_non_http_scheme() {
+        assert!("https://192.168.1.1/desc.xml".parse::<Url>().is_err());
+    }
+
+    #[test]
+    fn extract_tag_finds_first_matching_element() {
+        let xml = "<root><controlURL>/ctl/IPConn</controlURL></root>";
+        assert_eq!(extract_tag(xml, "controlURL"), Some("/ctl/IPConn"));
+    }
+
+    #[test]
+    fn extract_tag_trims_whitespace() {
+        let xml = "<NewExternalIPAddress>  203.0.113.7  </NewExternalIPAddress>";
+        assert_eq!(
+            extract_tag(xml, "NewExternalIPAddress"),
+            Some("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn extract_tag_missing_returns_none() {
+        let xml = "<root><foo>bar</foo></root>";
+        assert_eq!(extract_tag(xml, "controlURL"), None);
+    }
+
+    #[test]
+    fn parse_location_reads_location_header() {
+        let response = "HTTP/1.1 200 OK\r\n\
+             CACHE-CONTROL: max-age=1800\r\n\
+             LOCATION: http://192.168.1.1:5000/rootDesc.xml\r\n\
+             ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+        let url = parse_location(response).unwrap();
+        assert_eq!(url.host, IpAddr::from([192, 168, 1, 1]));
+        assert_eq!(url.path, "/rootDesc.xml");
+    }
+
+    #[test]
+    fn parse_location_missing_header_errors() {
+        let response = "HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\n\r\n";
+        assert!(parse_location(response).is_err());
+    }
+
+    #[test]
+    fn parse_control_url_finds_wan_ip_connection_v1() {
+        let description_url: Url = "http://192.168.1.1:5000/rootDesc.xml".parse().unwrap();
+        let description = "<service>\
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+<controlURL>/ctl/IPConn</controlURL>\
+</service>";
+
+        let (url, service) = parse_control_url(&description_url, description).unwrap();
+        assert_eq!(service, "urn:schemas-upnp-org:service:WANIPConnection:1");
+        assert_eq!(url.host, description_url.host);
+        assert_eq!(url.port, description_url.port);
+        assert_eq!(url.path, "/ctl/IPConn");
+    }
+
+    #[test]
+    fn parse_control_url_finds_wan_ip_connection_v2() {
+        let description_url: Url = "http://192.168.1.1:5000/rootDesc.xml".parse().unwrap();
+        let description = "<service>\
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>\
+<controlURL>/ctl/IPConn</controlURL>\
+</service>";
+
+        let (_url, service) = parse_control_url(&description_url, description).unwrap();
+        assert_eq!(service, "urn:schemas-upnp-org:service:WANIPConnection:2");
+    }
+
+    #[test]
+    fn parse_control_url_absolute_control_url() {
+        let description_url: Url = "http://192.168.1.1:5000/rootDesc.xml".parse().unwrap();
+        let description = "<service>\
+<serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+<controlURL>http://192.168.1.1:5000/ctl/PPPConn</controlURL>\
+</service>";
+
+        let (url, _service) = parse_control_url(&description_url, description).unwrap();
+        assert_eq!(url.path, "/ctl/PPPConn");
+    }
+
+    #[test]
+    fn parse_control_url_errors_when_no_known_service_present() {
+        let description_url: Url = "http://192.168.1.1:5000/rootDesc.xml".parse().unwrap();
+        let description = "<service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType></service>";
+        assert!(parse_control_url(&description_url, description).is_err());
+    }
+
+    #[test]
+    fn parse_external_ip_reads_soap_response() {
+        let response = "<?xml version=\"1.0\"?>\
+<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+</u:GetExternalIPAddressResponse></s:Body></s:Envelope>";
+
+        assert_eq!(
+            parse_external_ip(response).unwrap(),
+            IpAddr::from([203, 0, 113, 7])
+        );
+    }
+
+    #[test]
+    fn parse_external_ip_missing_tag_errors() {
+        let response = "<s:Envelope><s:Body></s:Body></s:Envelope>";
+        assert!(parse_external_ip(response).is_err());
+    }
+}
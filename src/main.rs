@@ -21,25 +21,41 @@
 //!      -d, --delay DELAY    Time between monitoring pings (default: 500)
 //!      -l, --limit LIMIT    Max network hops before giving up finding a public IP (default: 30)
 //!      -e, --expiry EXPIRY  Max time to wait for a network reply (default: 250)
-use std::collections::HashMap;
-use std::net::{IpAddr,Ipv4Addr};
+//!      -b, --buckets BUCKETS
+//!                           Comma-separated list of `ping_seconds` histogram bucket bounds, in
+//!                           seconds (default: a ladder from 0.5ms to 2.5s)
+//!      -t, --target TARGET  Additional host/IP to monitor concurrently, alongside the
+//!                           auto-discovered first public hop (repeatable)
+//!      -r, --retrace-interval RETRACE-INTERVAL
+//!                           Seconds between re-tracing to detect first-hop route changes, 0 to
+//!                           disable (default: 300)
+//!      -D, --dest DEST      Host to trace towards when discovering the first public hop
+//!                           (default: 1.1.1.1)
+//!      -s, --source SOURCE  How to discover the first hop to monitor: `trace` or `gateway`
+//!                           (default: trace)
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 use std::time::Duration;
 
 use anyhow::{anyhow, Result};
+use futures::future::select_all;
 use futures::{pin_mut, StreamExt};
 use gumdrop::Options;
 #[macro_use]
 extern crate log;
+mod gateway;
 use metrics::{
-    describe_counter, describe_histogram, histogram, increment_counter, register_counter,
-    register_histogram, Unit,
+    describe_counter, describe_gauge, describe_histogram, gauge, histogram, increment_counter,
+    register_counter, register_gauge, register_histogram, Unit,
 };
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
 use netdiag::{trace::Node, Bind, Ping, Pinger, Protocol, Tracer};
 use tokio::net::lookup_host;
+use tokio::sync::watch;
 use tokio::time::sleep;
 
 const NETWORK_ERROR_DELAY: u64 = 10; // seconds before attempting to restart monitoring
+const LOSS_WINDOW_SIZE: usize = 100; // pings considered when computing the rolling loss ratio
 
 #[derive(Debug, Options)]
 pub struct Args {
@@ -63,6 +79,59 @@ pub struct Args {
     limit: u8,
     #[options(default = "250", help = "Max time to wait for a network reply")]
     expiry: u64,
+    #[options(
+        default = "0.0005,0.001,0.005,0.01,0.025,0.05,0.1,0.25,0.5,1.0,2.5",
+        help = "Comma-separated list of `ping_seconds` histogram bucket bounds, in seconds"
+    )]
+    buckets: String,
+    #[options(
+        help = "Additional host/IP to monitor concurrently, alongside the auto-discovered first public hop (repeatable)"
+    )]
+    target: Vec<String>,
+    #[options(
+        default = "300",
+        help = "Seconds between re-tracing to detect first-hop route changes, 0 to disable"
+    )]
+    retrace_interval: u64,
+    #[options(
+        default = "1.1.1.1",
+        help = "Host to trace towards when discovering the first public hop"
+    )]
+    dest: String,
+    #[options(
+        default = "trace",
+        help = "How to discover the first hop to monitor: `trace` or `gateway`"
+    )]
+    source: String,
+}
+
+/// Discover the first hop to monitor, either by tracerouting towards `dest` or by locating the
+/// LAN's UPnP gateway directly.
+async fn discover_first_hop(
+    source: &str,
+    proto: String,
+    probe_port: u16,
+    count: usize,
+    limit: u8,
+    expiry: u64,
+    dest: &str,
+) -> Result<IpAddr> {
+    match source.to_lowercase().as_str() {
+        "gateway" => gateway::discover_gateway_ip().await,
+        _ => trace_to_public_ip(proto, probe_port, count, limit, expiry, dest).await,
+    }
+}
+
+/// Parse a comma-separated list of second values into histogram bucket bounds.
+fn parse_buckets(buckets: &str) -> Result<Vec<f64>> {
+    buckets
+        .split(',')
+        .map(|b| {
+            b.trim()
+                .parse::<f64>()
+                .map_err(|_| anyhow!("invalid bucket value: {}", b))
+        })
+        .collect()
 }
 
 #[tokio::main]
@@ -75,6 +144,11 @@ async fn main() -> Result<()> {
         delay,
         limit,
         expiry,
+        buckets,
+        target,
+        retrace_interval,
+        dest,
+        source,
         ..
     } = args;
     let ping_interval_delay = Duration::from_millis(delay);
@@ -84,7 +158,9 @@ async fn main() -> Result<()> {
     env_logger::init();
 
     // Metrics
-    let builder = PrometheusBuilder::new();
+    let buckets = parse_buckets(&buckets)?;
+    let builder = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full("ping_seconds".to_string()), &buckets)?;
     builder
         .install()
         .expect("failed to install recorder/exporter");
@@ -92,27 +168,166 @@ async fn main() -> Result<()> {
 
     register_metrics();
 
-    info!("Path to the closest public IP:");
-    let public_ip = trace_to_public_ip(proto, probe_port, count, limit, expiry).await?;
-    info!("Found first public IP: {}", public_ip);
+    info!("Discovering first hop to monitor (source: {}):", source);
+    let public_ip =
+        discover_first_hop(&source, proto.clone(), probe_port, count, limit, expiry, &dest).await?;
+    info!("Found first hop: {}", public_ip);
+    report_monitored_target(public_ip);
+
+    if source.eq_ignore_ascii_case("gateway") && retrace_interval > 0 {
+        tokio::spawn(report_gateway_external_ip(retrace_interval));
+    }
+
+    let (route_tx, route_rx) = watch::channel(public_ip);
+    if retrace_interval > 0 {
+        tokio::spawn(retrace_loop(
+            source,
+            proto,
+            probe_port,
+            count,
+            limit,
+            expiry,
+            retrace_interval,
+            dest,
+            public_ip,
+            route_tx,
+        ));
+    }
+
+    let mut monitors = vec![tokio::spawn(monitor_target(
+        public_ip,
+        expiry,
+        ping_interval_delay,
+        network_error_delay,
+        Some(route_rx),
+    ))];
+    for target in target {
+        let addr = lookup_address(&target)
+            .await
+            .map_err(|err| anyhow!("invalid --target {}: {}", target, err))?;
+        monitors.push(tokio::spawn(monitor_target(
+            addr,
+            expiry,
+            ping_interval_delay,
+            network_error_delay,
+            None,
+        )));
+    }
+
+    info!("starting ping monitoring for {} target(s)", monitors.len());
+    // Every monitor loops forever by design, so the first one to finish has failed (or panicked)
+    // and we should exit rather than silently keep running the rest short-handed.
+    let (result, _index, _remaining) = select_all(monitors).await;
+    if let Err(err) = &result {
+        error!("a ping monitor task panicked: {}", err);
+    } else if let Ok(Err(err)) = &result {
+        error!("a ping monitor task exited with an error: {}", err);
+    }
+    result?
+}
+
+/// Re-trace to the first public IP on a timer, restarting monitoring of that hop if the route
+/// has changed.
+async fn retrace_loop(
+    source: String,
+    proto: String,
+    probe_port: u16,
+    count: usize,
+    limit: u8,
+    expiry: u64,
+    retrace_interval: u64,
+    dest: String,
+    mut current_ip: IpAddr,
+    route_tx: watch::Sender<IpAddr>,
+) {
+    let retrace_delay = Duration::from_secs(retrace_interval);
+    loop {
+        sleep(retrace_delay).await;
+        match discover_first_hop(&source, proto.clone(), probe_port, count, limit, expiry, &dest)
+            .await
+        {
+            Ok(new_ip) => {
+                report_monitored_target(new_ip);
+                if new_ip != current_ip {
+                    info!("first public hop changed from {} to {}", current_ip, new_ip);
+                    increment_counter!("route_changes_total");
+                    current_ip = new_ip;
+                    if route_tx.send(new_ip).is_err() {
+                        // No monitor is listening any more, nothing left to do.
+                        return;
+                    }
+                }
+            }
+            Err(err) => warn!("periodic retrace failed, keeping current route: {}", err),
+        }
+    }
+}
+
+/// Publish the current first-hop target as a gauge label, so route changes are visible even
+/// without diffing `route_changes_total` samples.
+fn report_monitored_target(ip: IpAddr) {
+    gauge!("monitored_target_info", 1.0, "ip" => ip.to_string());
+}
+
+/// Periodically query the IGD for its WAN IP and publish it as a gauge label, so WAN-IP changes
+/// are visible on the same dashboard as the rest of the monitoring.
+async fn report_gateway_external_ip(interval: u64) {
+    let interval = Duration::from_secs(interval);
+    loop {
+        match gateway::discover_external_ip().await {
+            Ok(ip) => gauge!("gateway_external_ip_info", 1.0, "ip" => ip.to_string()),
+            Err(err) => warn!("failed to query gateway external IP: {}", err),
+        }
+        sleep(interval).await;
+    }
+}
 
-    info!("starting ping monitoring");
+/// Continuously ping a single target, restarting the pinger after network errors. If
+/// `route_rx` is set, this monitors the auto-discovered first hop and will restart against a new
+/// address whenever one is published on the channel.
+async fn monitor_target(
+    mut addr: IpAddr,
+    expiry: u64,
+    ping_interval_delay: Duration,
+    network_error_delay: Duration,
+    mut route_rx: Option<watch::Receiver<IpAddr>>,
+) -> Result<()> {
     loop {
+        let target = addr.to_string();
         let pinger = Pinger::new(&Bind::default()).await?;
         // Note the usize::MAX here - there's no pinger that just keeps pinging. So, we set it to
         // an unreasonably high number, this will never stop on a 64 bit machine. However, we may
         // want to contribute back a change to make this an Option(usize) to choose whether to have
         // a count or not. Maybe open a ticket first to propose it?
         let ping = Ping {
-            addr: public_ip,
+            addr,
             count: std::usize::MAX,
             expiry: Duration::from_millis(expiry),
         };
 
-        record_ping_results(pinger, ping, ping_interval_delay).await?;
+        if let Some(rx) = route_rx.as_mut() {
+            tokio::select! {
+                result = record_ping_results(pinger, ping, ping_interval_delay, &target) => {
+                    result?;
+                }
+                changed = rx.changed() => {
+                    if changed.is_err() {
+                        // The retrace task is gone; keep monitoring the last known address.
+                        route_rx = None;
+                        continue;
+                    }
+                    addr = *rx.borrow();
+                    info!("restarting pinger against new first-hop address {}", addr);
+                    continue;
+                }
+            }
+        } else {
+            record_ping_results(pinger, ping, ping_interval_delay, &target).await?;
+        }
+
         // Since the stream terminates on a network error, we sleep then restart.
         sleep(network_error_delay).await;
-        debug!("restarting ping monitoring");
+        debug!("restarting ping monitoring for {}", target);
     }
 }
 
@@ -132,6 +347,36 @@ fn register_metrics() {
         Unit::Seconds,
         "Ping latency to first public network hop in seconds."
     );
+    register_counter!("route_changes_total");
+    describe_counter!(
+        "route_changes_total",
+        Unit::Count,
+        "How many times the discovered first public hop has changed."
+    );
+    register_gauge!("monitored_target_info");
+    describe_gauge!(
+        "monitored_target_info",
+        Unit::Count,
+        "Always 1; the `ip` label carries the currently monitored first-hop address."
+    );
+    register_gauge!("ping_jitter_seconds");
+    describe_gauge!(
+        "ping_jitter_seconds",
+        Unit::Seconds,
+        "RFC 3550-style interarrival jitter estimate over successful pings."
+    );
+    register_gauge!("ping_loss_ratio");
+    describe_gauge!(
+        "ping_loss_ratio",
+        Unit::Count,
+        "Fraction of the last 100 pings that timed out or errored, counted since the pinger last (re)started."
+    );
+    register_gauge!("gateway_external_ip_info");
+    describe_gauge!(
+        "gateway_external_ip_info",
+        Unit::Count,
+        "Always 1; the `ip` label carries the IGD gateway's current WAN address (--source gateway only)."
+    );
 }
 
 /// Ping a target and record latency and failures on those pings.  This is the heart of this app.
@@ -139,44 +384,73 @@ async fn record_ping_results(
     pinger: Pinger,
     ping: Ping,
     ping_interval_delay: Duration,
+    target: &str,
 ) -> Result<()> {
     let stream = pinger.ping(&ping).enumerate();
     pin_mut!(stream);
 
+    // Previous successful RTT, used for the RFC 3550 jitter estimate. Reset on a gap (timeout or
+    // error) so a missed ping doesn't look like a jitter spike.
+    let mut prev_rtt: Option<Duration> = None;
+    let mut jitter = 0.0_f64;
+    let mut window: VecDeque<bool> = VecDeque::with_capacity(LOSS_WINDOW_SIZE);
+
     // Note that this stream terminates when there's a network error.
     while let Some((n, item_res)) = stream.next().await {
-        match item_res {
+        let success = match item_res {
             // Ping result.
             Ok(item) => match item {
                 Some(d) => {
-                    increment_counter!("ping_count");
-                    histogram!("ping_seconds", d);
-                    debug!("seq {} RTT {:0.2?} ", n, d)
+                    increment_counter!("ping_count", "target" => target.to_string());
+                    histogram!("ping_seconds", d, "target" => target.to_string());
+                    debug!("seq {} RTT {:0.2?} ", n, d);
+
+                    if let Some(prev) = prev_rtt {
+                        let delta = (d.as_secs_f64() - prev.as_secs_f64()).abs();
+                        jitter += (delta - jitter) / 16.0;
+                        gauge!("ping_jitter_seconds", jitter, "target" => target.to_string());
+                    }
+                    prev_rtt = Some(d);
+                    true
                 }
                 None => {
-                    increment_counter!("ping_failed_count");
-                    debug!("seq {} timeout", n)
+                    increment_counter!("ping_failed_count", "target" => target.to_string());
+                    debug!("seq {} timeout", n);
+                    prev_rtt = None;
+                    false
                 }
             },
             // Error, usually a network error - network drops for example.
             Err(_err) => {
-                increment_counter!("ping_failed_count");
-                debug!("seq {} error (network unreachable?)", n)
+                increment_counter!("ping_failed_count", "target" => target.to_string());
+                debug!("seq {} error (network unreachable?)", n);
+                prev_rtt = None;
+                false
             }
+        };
+
+        if window.len() == LOSS_WINDOW_SIZE {
+            window.pop_front();
         }
+        window.push_back(success);
+        let losses = window.iter().filter(|ok| !**ok).count();
+        let loss_ratio = losses as f64 / window.len() as f64;
+        gauge!("ping_loss_ratio", loss_ratio, "target" => target.to_string());
+
         sleep(ping_interval_delay).await;
     }
 
     Ok(())
 }
 
-/// Find the first public IP address as traced towards `1.1.1.1`.
+/// Find the first public IP address as traced towards `dest`.
 async fn trace_to_public_ip(
     proto: String,
     probe_port: u16,
     count: usize,
     limit: u8,
     expiry: u64,
+    dest: &str,
 ) -> Result<IpAddr> {
     let proto = match proto.to_uppercase().as_str() {
         "ICMP" => Protocol::ICMP,
@@ -188,8 +462,8 @@ async fn trace_to_public_ip(
     let expiry = Duration::from_millis(expiry);
 
     // We only use this address to trace towards, we likely won't reach it unless we're at
-    // Cloudflare itself.
-    let addr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+    // the destination itself.
+    let addr = lookup_address(dest).await?;
     let bind = Bind::default();
 
     let tracer = Tracer::new(&bind).await?;
@@ -242,25 +516,26 @@ fn is_public_ipv4_address(addr: IpAddr) -> Option<IpAddr> {
     };
 }
 
-/// Resolve the IP Address of a requested host.
-/// Not yet required. May add an option to select host to trace towards.
-#[allow(dead_code)]
-async fn lookup_address(host: String) -> Result<IpAddr> {
+/// Resolve the IP address of a requested host, preferring an IPv4 result when the host
+/// resolves to both families.
+///
+/// We only have `is_private` with ipv4 addresses, so we discard ipv6-only results for now. This
+/// will change in later rust versions, it's nightly-only for ipv6 now.
+async fn lookup_address(host: &str) -> Result<IpAddr> {
     let addr = format!("{}:0", host);
-    let addr = lookup_host(&addr)
-        .await?
-        .next()
-        .ok_or_else(|| anyhow!("invalid target"))?
-        .ip();
-
-    // We only have is_private with ipv4 addresses, so we discard ipv6 addresses for now. This will
-    // change in later rust versions, it's nightly-only for ipv6 now.
-    let _ = match addr {
-        IpAddr::V4(ip4) => ip4,
-        IpAddr::V6(ip6) => return Err(anyhow!("{} is an ipv6 address - can't continue.", ip6)),
-    };
+    let mut ipv6 = None;
 
-    Ok(addr)
+    for ip in lookup_host(&addr).await?.map(|socket| socket.ip()) {
+        match ip {
+            IpAddr::V4(_) => return Ok(ip),
+            IpAddr::V6(_) => ipv6.get_or_insert(ip),
+        };
+    }
+
+    match ipv6 {
+        Some(ip6) => Err(anyhow!("{} only resolved to an ipv6 address - can't continue.", ip6)),
+        None => Err(anyhow!("invalid target")),
+    }
 }
 
 /// Print out the currently found path to an address.